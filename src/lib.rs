@@ -1,14 +1,20 @@
 use wasm_bindgen::prelude::*;
 use lazy_static::lazy_static;
 use syntect::{
-    highlighting::{ThemeSet, Theme},
+    dumps::from_uncompressed_data,
+    highlighting::{Color, Theme, ThemeSet},
     parsing::{SyntaxSet, SyntaxReference},
-    html::{append_highlighted_html_for_styled_line, IncludeBackground},
+    html::{append_highlighted_html_for_styled_line, ClassStyle, ClassedHTMLGenerator, IncludeBackground},
     util::LinesWithEndings,
     easy::HighlightLines,
 };
 use std::path::Path;
+use std::sync::Mutex;
 use mime_sniffer::MimeTypeSniffer;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "tree-sitter")]
+mod tree_sitter_highlighter;
 
 extern crate wee_alloc;
 
@@ -16,69 +22,231 @@ extern crate wee_alloc;
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
+// Mutex so add_syntaxes/add_theme can mutate these at runtime.
 lazy_static! {
-    static ref SYNTAX_SET: SyntaxSet = SyntaxSet::load_defaults_newlines();
+    static ref SYNTAX_SET: Mutex<SyntaxSet> = Mutex::new(SyntaxSet::load_defaults_newlines());
 }
 
 lazy_static!{
-    static ref THEME_SET: ThemeSet = ThemeSet::load_defaults();
+    static ref THEME_SET: Mutex<ThemeSet> = Mutex::new(ThemeSet::load_defaults());
 }
 
 #[wasm_bindgen(js_name = "highlight_file")]
-pub fn highlight_file_js(code: String, filepath: String, is_light_theme: bool, highlight_long_lines: bool) -> Result<String, JsValue> {
-    highlight(&code, &filepath, is_light_theme, highlight_long_lines).map_err(|e| e.into())
+pub fn highlight_file_js(code: String, filepath: String, is_light_theme: bool, highlight_long_lines: bool, language: Option<String>) -> Result<String, JsValue> {
+    highlight(&code, &filepath, is_light_theme, highlight_long_lines, language.as_deref()).map_err(|e| e.into())
 }
 
-pub fn highlight(code: &str, filepath: &str, is_light_theme: bool, highlight_long_lines: bool) -> Result<String, HighlightError>  {
+pub fn highlight(code: &str, filepath: &str, is_light_theme: bool, highlight_long_lines: bool, language: Option<&str>) -> Result<String, HighlightError>  {
     if is_binary(&code.as_bytes()) {
         return Err(HighlightError::Binary)
     }
 
-    // TODO (@camdencheek): I think we can configure syntect to just output class names rather than
-    // in-line styles. We should consider doing this so the syntax highlighting can rely on the
-    // site's CSS rather than on the compiled-in theme files.
-    // https://docs.rs/syntect/4.5.0/syntect/html/struct.ClassedHTMLGenerator.html
+    let syntax_set = SYNTAX_SET.lock().unwrap();
+    let syntax_def = find_syntax_def(&syntax_set, language, filepath, code);
+
+    let theme_set = THEME_SET.lock().unwrap();
     let theme = if is_light_theme {
-        THEME_SET.themes.get("Sourcegraph (light)").expect("theme should be compiled with the binary")
+        theme_set.themes.get("Sourcegraph (light)").expect("theme should be compiled with the binary")
     } else {
-        THEME_SET.themes.get("Sourcegraph").expect("theme should be compiled with the binary")
+        theme_set.themes.get("Sourcegraph").expect("theme should be compiled with the binary")
     };
 
-    // Determine syntax definition by extension.
-    let path = Path::new(&filepath);
-    let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
-    let extension = path.extension().and_then(|x| x.to_str()).unwrap_or("");
+    // Callers that need the theme's background/foreground colors use `highlight_with_metadata`.
+
+    Ok(highlight_file(&code, &syntax_set, &syntax_def, theme, highlight_long_lines))
+}
+
+/// Like `highlight_file`, but emits `class="..."` tokens instead of inline `style="..."` colors.
+#[wasm_bindgen(js_name = "highlight_file_classed")]
+pub fn highlight_file_classed_js(code: String, filepath: String, highlight_long_lines: bool, language: Option<String>) -> Result<String, JsValue> {
+    highlight_classed(&code, &filepath, highlight_long_lines, language.as_deref()).map_err(|e| e.into())
+}
+
+pub fn highlight_classed(code: &str, filepath: &str, highlight_long_lines: bool, language: Option<&str>) -> Result<String, HighlightError> {
+    if is_binary(&code.as_bytes()) {
+        return Err(HighlightError::Binary)
+    }
+
+    let syntax_set = SYNTAX_SET.lock().unwrap();
+    let syntax_def = find_syntax_def(&syntax_set, language, filepath, code);
+
+    // Prefer tree-sitter over syntect if a grammar is registered under the requested `language`
+    // or, failing that, under the resolved syntax name. Only
+    // wired in here (not `highlight`) because tree-sitter only ever emits `class="..."` spans —
+    // it has no inline-styled output to offer the themed path.
+    #[cfg(feature = "tree-sitter")]
+    if let Some(html) = language
+        .and_then(|lang| tree_sitter_highlighter::highlight(lang, code))
+        .or_else(|| tree_sitter_highlighter::highlight(&syntax_def.name, code))
+    {
+        return Ok(html);
+    }
+
+    Ok(highlight_file_classed(&code, &syntax_set, &syntax_def, highlight_long_lines))
+}
+
+/// Highlighted HTML plus the detected syntax and the theme's colors.
+#[derive(Serialize)]
+pub struct HighlightResult {
+    pub html: String,
+    pub syntax: String,
+    pub fell_back_to_plain_text: bool,
+    pub background: String,
+    pub foreground: String,
+}
+
+#[wasm_bindgen(js_name = "highlight_file_with_metadata")]
+pub fn highlight_file_with_metadata_js(code: String, filepath: String, is_light_theme: bool, highlight_long_lines: bool, language: Option<String>) -> Result<JsValue, JsValue> {
+    let result = highlight_with_metadata(&code, &filepath, is_light_theme, highlight_long_lines, language.as_deref()).map_err::<JsValue, _>(|e| e.into())?;
+    JsValue::from_serde(&result).map_err(|e| JsValue::from(e.to_string()))
+}
+
+pub fn highlight_with_metadata(code: &str, filepath: &str, is_light_theme: bool, highlight_long_lines: bool, language: Option<&str>) -> Result<HighlightResult, HighlightError> {
+    if is_binary(&code.as_bytes()) {
+        return Err(HighlightError::Binary)
+    }
+
+    let theme_set = THEME_SET.lock().unwrap();
+    let theme = if is_light_theme {
+        theme_set.themes.get("Sourcegraph (light)").expect("theme should be compiled with the binary")
+    } else {
+        theme_set.themes.get("Sourcegraph").expect("theme should be compiled with the binary")
+    };
+
+    let syntax_set = SYNTAX_SET.lock().unwrap();
+    let syntax_def = find_syntax_def(&syntax_set, language, filepath, code);
+    let fell_back_to_plain_text = syntax_def.name == syntax_set.find_syntax_plain_text().name;
+
+    let html = highlight_file(&code, &syntax_set, &syntax_def, theme, highlight_long_lines);
+
+    Ok(HighlightResult {
+        html,
+        syntax: syntax_def.name.clone(),
+        fell_back_to_plain_text,
+        background: theme.settings.background.map(color_to_hex).unwrap_or_else(|| "#ffffff".to_string()),
+        foreground: theme.settings.foreground.map(color_to_hex).unwrap_or_else(|| "#000000".to_string()),
+    })
+}
+
+fn color_to_hex(c: Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b)
+}
+
+/// A single 1-based, inclusive line range, as sent from JS (`{start, end}`).
+#[derive(Deserialize)]
+struct RangeInput {
+    start: usize,
+    end: usize,
+}
+
+/// Highlights just the lines covered by `ranges` (a JS array of 1-based inclusive `{start, end}`
+/// objects), returning one concatenated HTML table per range.
+#[wasm_bindgen(js_name = "highlight_ranges")]
+pub fn highlight_ranges_js(code: String, filepath: String, is_light_theme: bool, highlight_long_lines: bool, language: Option<String>, ranges: JsValue) -> Result<Vec<String>, JsValue> {
+    let ranges: Vec<RangeInput> = ranges.into_serde().map_err(|e| JsValue::from(e.to_string()))?;
+    let line_ranges: Vec<LineRange> = ranges.into_iter().map(|r| LineRange { start: r.start, end: r.end }).collect();
+
+    let rows = highlight_line_ranges(&code, &filepath, is_light_theme, highlight_long_lines, language.as_deref(), &line_ranges).map_err::<JsValue, _>(|e| e.into())?;
+
+    Ok(rows.into_iter().map(|lines| {
+        let mut html = String::with_capacity(8 * lines.len());
+        start_highlighted_table(&mut html);
+        for line in lines {
+            html.push_str(&line);
+        }
+        end_highlighted_table(&mut html);
+        html
+    }).collect())
+}
+
+pub fn highlight_line_ranges(code: &str, filepath: &str, is_light_theme: bool, highlight_long_lines: bool, language: Option<&str>, ranges: &[LineRange]) -> Result<Vec<Vec<String>>, HighlightError> {
+    if is_binary(&code.as_bytes()) {
+        return Err(HighlightError::Binary)
+    }
+
+    let theme_set = THEME_SET.lock().unwrap();
+    let theme = if is_light_theme {
+        theme_set.themes.get("Sourcegraph (light)").expect("theme should be compiled with the binary")
+    } else {
+        theme_set.themes.get("Sourcegraph").expect("theme should be compiled with the binary")
+    };
+
+    let syntax_set = SYNTAX_SET.lock().unwrap();
+    let syntax_def = find_syntax_def(&syntax_set, language, filepath, code);
+
+    Ok(highlight_ranges(&code, &syntax_set, &syntax_def, theme, highlight_long_lines, ranges))
+}
 
+/// Merges a bincode-encoded `SyntaxSet` dump into the active set.
+#[wasm_bindgen(js_name = "add_syntaxes")]
+pub fn add_syntaxes_js(bytes: &[u8]) -> Result<(), JsValue> {
+    add_syntaxes(bytes).map_err(|e| e.into())
+}
+
+pub fn add_syntaxes(bytes: &[u8]) -> Result<(), HighlightError> {
+    let extra: SyntaxSet = from_uncompressed_data(bytes).map_err(|e| HighlightError::Deserialize(e.to_string()))?;
+
+    let mut syntax_set = SYNTAX_SET.lock().unwrap();
+    let mut builder = syntax_set.clone().into_builder();
+    builder.add_syntaxes(extra);
+    *syntax_set = builder.build();
+
+    Ok(())
+}
+
+/// Registers a bincode-encoded `Theme` dump under `name` in the theme set. The highlight functions
+/// only pick "Sourcegraph" or "Sourcegraph (light)", so a theme added under any other name is
+/// stored but not used by them; one added under either of those names replaces that built-in.
+#[wasm_bindgen(js_name = "add_theme")]
+pub fn add_theme_js(name: String, bytes: &[u8]) -> Result<(), JsValue> {
+    add_theme(name, bytes).map_err(|e| e.into())
+}
+
+pub fn add_theme(name: String, bytes: &[u8]) -> Result<(), HighlightError> {
+    let theme: Theme = from_uncompressed_data(bytes).map_err(|e| HighlightError::Deserialize(e.to_string()))?;
+
+    THEME_SET.lock().unwrap().themes.insert(name, theme);
+
+    Ok(())
+}
+
+// If given, try `language` directly before falling back to extension/first-line detection.
+fn find_syntax_def<'a>(ss: &'a SyntaxSet, language: Option<&str>, filepath: &str, code: &str) -> &'a SyntaxReference {
+    if let Some(lang) = language {
+        if let Some(syntax_def) = ss.find_syntax_by_token(lang).or_else(|| ss.find_syntax_by_name(lang)) {
+            return syntax_def;
+        }
+    }
+
+    // Determine syntax definition by extension.
+    //
     // Split the input path ("foo/myfile.go") into file name
     // ("myfile.go") and extension ("go").
-
+    //
     // To determine the syntax definition, we must first check using the
     // filename as some syntaxes match an "extension" that is actually a
     // whole file name (e.g. "Dockerfile" or "CMakeLists.txt"); see e.g. https://github.com/trishume/syntect/pull/170
     //
     // After that, if we do not find any syntax, we can actually check by
     // extension and lastly via the first line of the code.
+    let path = Path::new(&filepath);
+    let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let extension = path.extension().and_then(|x| x.to_str()).unwrap_or("");
 
     // First try to find a syntax whose "extension" matches our file
     // name. This is done due to some syntaxes matching an "extension"
     // that is actually a whole file name (e.g. "Dockerfile" or "CMakeLists.txt")
     // see https://github.com/trishume/syntect/pull/170
-    let syntax_def = SYNTAX_SET.find_syntax_by_extension(file_name).or_else(|| {
+    ss.find_syntax_by_extension(file_name).or_else(|| {
         // Now try to find the syntax by the actual file extension.
-        SYNTAX_SET.find_syntax_by_extension(extension)
+        ss.find_syntax_by_extension(extension)
     }).or_else(|| {
         // Fall back: Determine syntax definition by first line.
-        SYNTAX_SET.find_syntax_by_first_line(&code)
+        ss.find_syntax_by_first_line(&code)
     }).unwrap_or_else(|| {
         // Render plain text, so the user gets the same HTML output structure.
-        SYNTAX_SET.find_syntax_plain_text()
-    });
-
-
-    // TODO(slimsag): return the theme's background color (and other info??) to caller?
-    // https://github.com/trishume/syntect/blob/c8b47758a3872d478c7fc740782cd468b2c0a96b/examples/synhtml.rs#L24
-
-    Ok(highlight_file(&code, &SYNTAX_SET, &syntax_def, theme, highlight_long_lines))
+        ss.find_syntax_plain_text()
+    })
 }
 
 fn is_binary(content: &[u8]) -> bool {
@@ -110,12 +278,33 @@ fn highlighted_rows<'a>(code: &'a str, ss: &'a SyntaxSet, syntax: &'a SyntaxRefe
     })
 }
 
+fn highlighted_rows_classed<'a>(code: &'a str, ss: &'a SyntaxSet, syntax: &'a SyntaxReference, highlight_long_lines: bool) -> impl Iterator<Item = String> + 'a {
+    LinesWithEndings::from(code).enumerate().map(move |(i, line)| {
+        let mut highlighted = String::with_capacity(8 * line.len());
+        start_table_row(&mut highlighted, i+1);
+        if !highlight_long_lines && line.len() > 2000 {
+            highlighted.push_str(line);
+        } else {
+            let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, ss, ClassStyle::SpacedPrefixed { prefix: "prefix-" });
+            match generator.parse_html_for_line_which_includes_newline(line) {
+                Ok(()) => highlighted.push_str(&generator.finalize()),
+                // Same fallback as the too-long-line branch above: render the raw line rather
+                // than silently dropping it.
+                Err(_) => highlighted.push_str(line),
+            }
+        }
+        end_table_row(&mut highlighted);
+        highlighted
+    })
+}
+
 fn highlight_ranges(code: &str, ss: &SyntaxSet, syntax: &SyntaxReference, theme: &Theme, highlight_long_lines: bool, ranges: &[LineRange]) -> Vec<Vec<String>> {
     let mut output: Vec<Vec<String>> = vec![Vec::with_capacity(10); ranges.len()];
-    for row in highlighted_rows(code, ss, syntax, theme, highlight_long_lines) {
-        for (i, v) in output.iter_mut().enumerate() {
-            if ranges[i].contains(i) {
-                v.push(row.clone());
+    for (i, row) in highlighted_rows(code, ss, syntax, theme, highlight_long_lines).enumerate() {
+        let line_num = i + 1;
+        for (j, range) in ranges.iter().enumerate() {
+            if range.contains(line_num) {
+                output[j].push(row.clone());
             }
         }
     }
@@ -135,31 +324,45 @@ fn highlight_file(code: &str, ss: &SyntaxSet, syntax: &SyntaxReference, theme: &
     output
 }
 
-fn start_highlighted_table(s: &mut String) {
+fn highlight_file_classed(code: &str, ss: &SyntaxSet, syntax: &SyntaxReference, highlight_long_lines: bool) -> String {
+    let mut output = String::with_capacity(8 * code.len());
+    start_highlighted_table(&mut output);
+
+    for line in highlighted_rows_classed(code, ss, syntax, highlight_long_lines) {
+        output.push_str(&line)
+    }
+
+    end_highlighted_table(&mut output);
+    output
+}
+
+pub(crate) fn start_highlighted_table(s: &mut String) {
     s.push_str("<table><tbody>")
 }
 
-fn end_highlighted_table(s: &mut String) {
+pub(crate) fn end_highlighted_table(s: &mut String) {
     s.push_str("</tbody></table>");
 }
 
-fn start_table_row(s: &mut String, row_num: usize) {
+pub(crate) fn start_table_row(s: &mut String, row_num: usize) {
     s.push_str(&format!("<tr><td class=\"line\" data-line=\"{}\"></td><td class=\"code\"><div>", row_num));
 }
 
-fn end_table_row(s: &mut String) {
+pub(crate) fn end_table_row(s: &mut String) {
     s.push_str("</div></td></tr>");
 }
 
 #[derive(Debug)]
 pub enum HighlightError {
     Binary,
+    Deserialize(String),
 }
 
 impl From<HighlightError> for JsValue {
     fn from(e: HighlightError) -> JsValue {
         match e {
             HighlightError::Binary => JsValue::from("cannot render binary file"),
+            HighlightError::Deserialize(msg) => JsValue::from(format!("failed to deserialize dump: {}", msg)),
         }
     }
 }
@@ -167,7 +370,7 @@ impl From<HighlightError> for JsValue {
 #[cfg(test)]
 mod tests {
     use std::fs;
-    use super::highlight;
+    use super::{highlight, highlight_line_ranges, highlight_with_metadata, LineRange};
     use html_diff::get_differences;
 
     struct Asset {
@@ -185,7 +388,7 @@ mod tests {
 
     fn test_asset(id: usize) {
         let asset = read_asset(id);
-        let result = highlight(&asset.input, &asset.filename, true, true).unwrap();
+        let result = highlight(&asset.input, &asset.filename, true, true, None).unwrap();
         assert_diff(&result, &asset.output);
     }
 
@@ -209,6 +412,39 @@ mod tests {
     fn asset2() {
         test_asset(2)
     }
+
+    #[test]
+    fn language_override_wins_over_extension_detection() {
+        // "go" doesn't match anything about "notes.txt" by extension or first line, so without
+        // the override this would detect plain text.
+        let result = highlight_with_metadata("package main\n", "notes.txt", true, true, Some("go")).unwrap();
+        assert_eq!(result.syntax, "Go");
+        assert!(!result.fell_back_to_plain_text);
+    }
+
+    #[test]
+    fn highlight_ranges_routes_rows_to_matching_ranges() {
+        let code = "line1\nline2\nline3\nline4\nline5\n";
+        let ranges = vec![
+            LineRange { start: 1, end: 2 }, // lines 1 and 2
+            LineRange { start: 2, end: 4 }, // overlaps the first range at line 2
+        ];
+
+        let result = highlight_line_ranges(code, "test.txt", true, true, None, &ranges).unwrap();
+        assert_eq!(result.len(), 2);
+
+        let range0 = result[0].concat();
+        assert!(range0.contains("data-line=\"1\""));
+        assert!(range0.contains("data-line=\"2\""));
+        assert!(!range0.contains("data-line=\"3\""));
+
+        let range1 = result[1].concat();
+        assert!(!range1.contains("data-line=\"1\""));
+        assert!(range1.contains("data-line=\"2\""));
+        assert!(range1.contains("data-line=\"3\""));
+        assert!(range1.contains("data-line=\"4\""));
+        assert!(!range1.contains("data-line=\"5\""));
+    }
 }
 
 struct LineRange{
@@ -8,6 +8,6 @@ fn main() {
     let content = fs::read_to_string(&file).unwrap();
     let file_name = Path::new(&file).file_name().unwrap().to_str().unwrap();
 
-    print!("{}", highlight(&content, file_name, true, true).unwrap());
+    print!("{}", highlight(&content, file_name, true, true, None).unwrap());
 
 }
@@ -0,0 +1,188 @@
+//! Optional tree-sitter-based highlighting backend, for grammars syntect's regex-based
+//! TextMate/Sublime definitions miss or handle poorly. Gated behind the `tree-sitter` feature
+//! since it pulls in `tree-sitter`/`tree-sitter-highlight` plus one crate per registered
+//! grammar, none of which the default syntect-only build needs.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+use tree_sitter::Language;
+use tree_sitter_highlight::{Highlighter, HighlightConfiguration, HighlightEvent};
+
+use crate::{end_highlighted_table, end_table_row, start_highlighted_table, start_table_row};
+
+// The same token classes `highlight_file_classed` emits, so a tree-sitter-highlighted file and a
+// syntect-highlighted one can share a single stylesheet.
+const HIGHLIGHT_NAMES: &[&str] = &[
+    "keyword",
+    "function",
+    "string",
+    "comment",
+    "type",
+    "variable",
+    "constant",
+    "number",
+    "operator",
+    "property",
+];
+
+/// Runs a single registered grammar's `highlights.scm` query over source text and renders the
+/// same `<tr><td class="line">…<td class="code"><div>` row scaffolding the syntect-backed modes
+/// use, with tokens wrapped in `<span class="prefix-...">` named after the `HIGHLIGHT_NAMES`
+/// entry their capture matches.
+pub struct TreeSitterHighlighter {
+    config: HighlightConfiguration,
+}
+
+impl TreeSitterHighlighter {
+    pub fn new(language: Language, highlights_query: &str) -> Result<Self, String> {
+        let mut config = HighlightConfiguration::new(language, "", highlights_query, "", "")
+            .map_err(|e| e.to_string())?;
+        config.configure(HIGHLIGHT_NAMES);
+
+        Ok(TreeSitterHighlighter { config })
+    }
+
+    pub fn highlight(&self, code: &str) -> Result<String, String> {
+        let mut highlighter = Highlighter::new();
+        let events = highlighter
+            .highlight(&self.config, code.as_bytes(), None, |_| None)
+            .map_err(|e| e.to_string())?;
+
+        let mut output = String::with_capacity(8 * code.len());
+        start_highlighted_table(&mut output);
+
+        let mut row = String::new();
+        let mut row_num = 1;
+        let mut open_classes: Vec<&str> = Vec::new();
+        start_table_row(&mut row, row_num);
+
+        for event in events {
+            match event.map_err(|e| e.to_string())? {
+                HighlightEvent::HighlightStart(h) => {
+                    let class = HIGHLIGHT_NAMES[h.0];
+                    open_classes.push(class);
+                    row.push_str(&format!("<span class=\"prefix-{}\">", class));
+                }
+                HighlightEvent::HighlightEnd => {
+                    open_classes.pop();
+                    row.push_str("</span>");
+                }
+                HighlightEvent::Source { start, end } => {
+                    for (i, line) in code[start..end].split('\n').enumerate() {
+                        if i > 0 {
+                            end_table_row(&mut row);
+                            output.push_str(&row);
+                            row.clear();
+                            row_num += 1;
+                            start_table_row(&mut row, row_num);
+                            for class in &open_classes {
+                                row.push_str(&format!("<span class=\"prefix-{}\">", class));
+                            }
+                        }
+                        escape_html(line, &mut row);
+                    }
+                }
+            }
+        }
+
+        end_table_row(&mut row);
+        output.push_str(&row);
+        end_highlighted_table(&mut output);
+
+        Ok(output)
+    }
+}
+
+// `HighlightEvent::Source` gives us raw source bytes, so they need the same escaping
+// `append_highlighted_html_for_styled_line`/`ClassedHTMLGenerator` apply before landing in the
+// output table.
+fn escape_html(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\'' => out.push_str("&#39;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<HashMap<String, TreeSitterHighlighter>> = Mutex::new(HashMap::new());
+}
+
+/// Registers a compiled grammar under `name` so `highlight` can find it by the same language
+/// name/token the syntect path resolves. Unlike `add_syntaxes`/`add_theme`, this isn't
+/// `#[wasm_bindgen]`-exposed: a `tree_sitter::Language` is a native parser table, not data a JS
+/// host can hand across the wasm boundary, so grammars are registered by Rust code that's
+/// statically linked against the grammar crate (e.g. from a build-time `lazy_static` or an
+/// embedder's own init path), not loaded at runtime like syntax/theme dumps are.
+pub fn register_language(name: String, language: Language, highlights_query: &str) -> Result<(), String> {
+    let highlighter = TreeSitterHighlighter::new(language, highlights_query)?;
+    REGISTRY.lock().unwrap().insert(name, highlighter);
+    Ok(())
+}
+
+/// Highlights `code` with the grammar registered under `name`, if any. Returns `None` when no
+/// grammar is registered for that name, so the caller can fall back to the syntect backend.
+pub fn highlight(name: &str, code: &str) -> Option<String> {
+    let registry = REGISTRY.lock().unwrap();
+    registry.get(name)?.highlight(code).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rust_highlighter() -> TreeSitterHighlighter {
+        TreeSitterHighlighter::new(tree_sitter_rust::language(), tree_sitter_rust::HIGHLIGHTS_QUERY).unwrap()
+    }
+
+    // Escaped source never holds a raw `<`, so every `<...>` in the output is markup.
+    fn strip_tags(html: &str) -> String {
+        let mut text = String::new();
+        let mut in_tag = false;
+        for c in html.chars() {
+            match c {
+                '<' => in_tag = true,
+                '>' => in_tag = false,
+                _ if !in_tag => text.push(c),
+                _ => {}
+            }
+        }
+        text
+    }
+
+    #[test]
+    fn escapes_html_special_characters_in_source() {
+        let html = rust_highlighter().highlight("let v: Vec<u8> = vec![1 < 2 && 2 > 1];\n").unwrap();
+        assert!(!html.contains("Vec<u8>"));
+        // `Vec` and `u8` are type captures wrapped in their own spans, so check the text between tags.
+        assert!(strip_tags(&html).contains("Vec&lt;u8&gt;"));
+        assert!(html.contains("&amp;&amp;"));
+    }
+
+    #[test]
+    fn wraps_captures_in_prefixed_classes() {
+        let html = rust_highlighter().highlight("// a comment\n").unwrap();
+        assert!(html.contains("class=\"prefix-comment\""));
+    }
+
+    #[test]
+    fn splits_multiline_source_into_table_rows() {
+        let html = rust_highlighter().highlight("fn a() {}\nfn b() {}\n").unwrap();
+        assert!(html.contains("data-line=\"1\""));
+        assert!(html.contains("data-line=\"2\""));
+    }
+
+    #[test]
+    fn register_language_makes_it_reachable_by_name() {
+        register_language("rust".to_string(), tree_sitter_rust::language(), tree_sitter_rust::HIGHLIGHTS_QUERY).unwrap();
+
+        assert!(highlight("rust", "fn a() {}\n").is_some());
+        assert!(highlight("no-such-language", "fn a() {}\n").is_none());
+    }
+}